@@ -8,20 +8,26 @@ use std::ops::Range;
 use std::path::PathBuf;
 use tiktoken_rs::ChatCompletionRequestMessage;
 
+// A retrieved snippet whose wrapped text reaches this many tokens is left out of the prompt.
+// Before that, an oversized match is trimmed to the whole declarations it overlaps, each of
+// which fits within this many tokens on its own; the trimmed snippet as a whole is not capped.
+const MAXIMUM_SNIPPET_TOKEN_COUNT: usize = 500;
+
 pub struct PromptCodeSnippet {
     path: Option<PathBuf>,
     language_name: Option<String>,
     content: String,
+    score: f32,
 }
 
 impl PromptCodeSnippet {
-    pub fn new(search_result: SearchResult, cx: &AsyncAppContext) -> Self {
+    pub fn new(search_result: SearchResult, model: &str, cx: &AsyncAppContext) -> Self {
+        let score = search_result.similarity;
         let (content, language_name, file_path) =
             search_result.buffer.read_with(cx, |buffer, _| {
                 let snapshot = buffer.snapshot();
-                let content = snapshot
-                    .text_for_range(search_result.range.clone())
-                    .collect::<String>();
+                let content =
+                    Self::syntax_aware_trim(&snapshot, search_result.range.clone(), model);
 
                 let language_name = buffer
                     .language()
@@ -38,8 +44,63 @@ impl PromptCodeSnippet {
             path: file_path,
             language_name,
             content,
+            score,
         }
     }
+
+    /// Shrinks `range` down to the nearest enclosing complete declaration(s) that fit within
+    /// `MAXIMUM_SNIPPET_TOKEN_COUNT`, instead of cutting at the raw (possibly mid-declaration)
+    /// byte offset. Declarations that still don't fit are collapsed so their signature survives
+    /// even though their body doesn't. The limit applies to each declaration separately: the
+    /// declarations are concatenated without a cap on their combined size.
+    fn syntax_aware_trim(buffer: &BufferSnapshot, range: Range<usize>, model: &str) -> String {
+        let raw = buffer.text_for_range(range.clone()).collect::<String>();
+        let Ok(encoding) = tiktoken_rs::get_bpe_from_model(model) else {
+            return raw;
+        };
+        if encoding.encode_with_special_tokens(&raw).len() <= MAXIMUM_SNIPPET_TOKEN_COUNT {
+            return raw;
+        }
+
+        let item_ranges = item_ranges_for(buffer, range.clone());
+        if item_ranges.is_empty() {
+            return raw;
+        }
+
+        // `item_ranges` contains every overlapping `@item` node, including ancestors (e.g. the
+        // enclosing `impl_item`) alongside the nested `function_item`(s) within it — they aren't
+        // mutually exclusive matches. Keep only the innermost node per cluster, i.e. drop any
+        // range that itself contains another range in the list, or we'd emit the whole ancestor
+        // declaration *and* a duplicate copy of the nested one.
+        let mut item_ranges = item_ranges
+            .iter()
+            .filter(|item_range| {
+                !item_ranges.iter().any(|other| {
+                    *other != **item_range
+                        && item_range.start <= other.start
+                        && item_range.end >= other.end
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        item_ranges.sort_unstable_by_key(|item_range| item_range.start);
+
+        let mut trimmed = String::new();
+        for item_range in item_ranges {
+            let declaration = buffer
+                .text_for_range(item_range.clone())
+                .collect::<String>();
+            if encoding.encode_with_special_tokens(&declaration).len()
+                <= MAXIMUM_SNIPPET_TOKEN_COUNT
+            {
+                trimmed.push_str(&declaration);
+            } else {
+                trimmed.push_str(&collapse_declaration(buffer, item_range));
+            }
+            trimmed.push('\n');
+        }
+        trimmed
+    }
 }
 
 impl ToString for PromptCodeSnippet {
@@ -56,16 +117,17 @@ impl ToString for PromptCodeSnippet {
     }
 }
 
-#[allow(dead_code)]
-fn summarize(buffer: &BufferSnapshot, selected_range: Range<impl ToOffset>) -> String {
-    #[derive(Debug)]
-    struct Match {
-        collapse: Range<usize>,
-        keep: Vec<Range<usize>>,
-    }
+#[derive(Debug)]
+struct CollapseMatch {
+    collapse: Range<usize>,
+    keep: Vec<Range<usize>>,
+}
 
-    let selected_range = selected_range.to_offset(buffer);
-    let mut ts_matches = buffer.matches(0..buffer.len(), |grammar| {
+/// Finds every collapsible (`@collapse`) node within `range`, along with the byte ranges
+/// (`@keep`) that should survive the collapse (e.g. a function body's surrounding braces).
+/// Shared by `summarize` (whole-file outline) and `collapse_declaration` (single-item elision).
+fn collapse_matches_for_range(buffer: &BufferSnapshot, range: Range<usize>) -> Vec<CollapseMatch> {
+    let mut ts_matches = buffer.matches(range, |grammar| {
         Some(&grammar.embedding_config.as_ref()?.query)
     });
     let configs = ts_matches
@@ -92,17 +154,113 @@ fn summarize(buffer: &BufferSnapshot, selected_range: Range<impl ToOffset>) -> S
                 }
             }
             ts_matches.advance();
-            matches.push(Match { collapse, keep });
+            matches.push(CollapseMatch { collapse, keep });
         } else {
             ts_matches.advance();
         }
     }
     matches.sort_unstable_by_key(|mat| (mat.collapse.start, Reverse(mat.collapse.end)));
-    let mut matches = matches.into_iter().peekable();
+    matches
+}
+
+/// Finds every `@item` node (function, struct, impl, trait, ...) that overlaps `range`, the
+/// item-side counterpart to `collapse_matches_for_range`. Ancestors and the descendants nested
+/// inside them are both included unfiltered; callers decide which of the overlapping nodes they
+/// actually want (e.g. the innermost one, or the smallest one fully containing `range`).
+fn item_ranges_for(buffer: &BufferSnapshot, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut ts_matches = buffer.matches(0..buffer.len(), |grammar| {
+        Some(&grammar.embedding_config.as_ref()?.query)
+    });
+    let configs = ts_matches
+        .grammars()
+        .iter()
+        .map(|g| g.embedding_config.as_ref().unwrap())
+        .collect::<Vec<_>>();
+    let mut item_ranges = Vec::new();
+    while let Some(mat) = ts_matches.peek() {
+        let config = &configs[mat.grammar_index];
+        if let Some(item_range) = mat.captures.iter().find_map(|cap| {
+            if Some(cap.index) == config.item_capture_ix {
+                Some(cap.node.byte_range())
+            } else {
+                None
+            }
+        }) {
+            if item_range.start < range.end && item_range.end > range.start {
+                item_ranges.push(item_range);
+            }
+        }
+        ts_matches.advance();
+    }
+    item_ranges
+}
+
+/// Collapses every node's body within `item_range` down to its signature, the same way
+/// `summarize` does for the whole file, but scoped to a single declaration and without any
+/// selection markers. Used to shrink an oversized retrieved snippet down to just its header.
+fn collapse_declaration(buffer: &BufferSnapshot, item_range: Range<usize>) -> String {
+    let mut matches = collapse_matches_for_range(buffer, item_range.clone())
+        .into_iter()
+        .peekable();
+
+    let mut declaration = String::new();
+    let mut offset = item_range.start;
+    while let Some(mat) = matches.next() {
+        while let Some(next_mat) = matches.peek() {
+            if mat.collapse.start <= next_mat.collapse.start
+                && mat.collapse.end >= next_mat.collapse.end
+            {
+                matches.next().unwrap();
+            } else {
+                break;
+            }
+        }
+
+        if offset > mat.collapse.start {
+            offset = cmp::max(offset, mat.collapse.end);
+            continue;
+        }
+
+        declaration.extend(buffer.text_for_range(offset..mat.collapse.start));
+        for keep in mat.keep {
+            declaration.extend(buffer.text_for_range(keep));
+        }
+        offset = mat.collapse.end;
+    }
+    declaration.extend(buffer.text_for_range(offset..item_range.end));
+    declaration
+}
+
+/// Writes the numbered `<|START:n|...|END:n|>` (or `<|START:n|>` for an empty selection) marker
+/// pair for the `ix`-th selected range (1-based in the emitted marker) into `out`.
+fn write_selection_marker(
+    out: &mut String,
+    buffer: &BufferSnapshot,
+    ix: usize,
+    range: Range<usize>,
+) {
+    let marker_ix = ix + 1;
+    if range.start == range.end {
+        write!(out, "<|START:{marker_ix}|>").unwrap();
+    } else {
+        write!(out, "<|START:{marker_ix}|").unwrap();
+        out.extend(buffer.text_for_range(range));
+        write!(out, "|END:{marker_ix}|>").unwrap();
+    }
+}
+
+/// Collapses every function/impl/block body down to its signature, the way `generate_content_prompt`
+/// does for an oversized file, while keeping every selected range verbatim and marked with its own
+/// numbered `<|START:n|...|END:n|>` pair. `selected_ranges` must be sorted and non-overlapping.
+/// A collapsed node is left uncollapsed whenever a selection intersects it.
+fn summarize(buffer: &BufferSnapshot, selected_ranges: &[Range<usize>]) -> String {
+    let mut matches = collapse_matches_for_range(buffer, 0..buffer.len())
+        .into_iter()
+        .peekable();
 
     let mut summary = String::new();
     let mut offset = 0;
-    let mut flushed_selection = false;
+    let mut next_selection_ix = 0;
     while let Some(mat) = matches.next() {
         // Keep extending the collapsed range if the next match surrounds
         // the current one.
@@ -122,26 +280,31 @@ fn summarize(buffer: &BufferSnapshot, selected_range: Range<impl ToOffset>) -> S
             continue;
         }
 
-        if offset <= selected_range.start && selected_range.start <= mat.collapse.end {
-            if !flushed_selection {
+        let mut intersects_match = false;
+        while let Some(selected_range) = selected_ranges.get(next_selection_ix) {
+            if offset <= selected_range.start && selected_range.start <= mat.collapse.end {
                 // The collapsed node ends after the selection starts, so we'll flush the selection first.
                 summary.extend(buffer.text_for_range(offset..selected_range.start));
-                summary.push_str("<|START|");
-                if selected_range.end == selected_range.start {
-                    summary.push_str(">");
-                } else {
-                    summary.extend(buffer.text_for_range(selected_range.clone()));
-                    summary.push_str("|END|>");
-                }
+                write_selection_marker(
+                    &mut summary,
+                    buffer,
+                    next_selection_ix,
+                    selected_range.clone(),
+                );
                 offset = selected_range.end;
-                flushed_selection = true;
-            }
 
-            // If the selection intersects the collapsed node, we won't collapse it.
-            if selected_range.end >= mat.collapse.start {
-                continue;
+                // If the selection intersects the collapsed node, we won't collapse it.
+                if selected_range.end >= mat.collapse.start {
+                    intersects_match = true;
+                }
+                next_selection_ix += 1;
+            } else {
+                break;
             }
         }
+        if intersects_match {
+            continue;
+        }
 
         summary.extend(buffer.text_for_range(offset..mat.collapse.start));
         for keep in mat.keep {
@@ -150,37 +313,63 @@ fn summarize(buffer: &BufferSnapshot, selected_range: Range<impl ToOffset>) -> S
         offset = mat.collapse.end;
     }
 
-    // Flush selection if we haven't already done so.
-    if !flushed_selection && offset <= selected_range.start {
-        summary.extend(buffer.text_for_range(offset..selected_range.start));
-        summary.push_str("<|START|");
-        if selected_range.end == selected_range.start {
-            summary.push_str(">");
-        } else {
-            summary.extend(buffer.text_for_range(selected_range.clone()));
-            summary.push_str("|END|>");
+    // Flush any selections we haven't already flushed.
+    while let Some(selected_range) = selected_ranges.get(next_selection_ix) {
+        if offset > selected_range.start {
+            next_selection_ix += 1;
+            continue;
         }
+        summary.extend(buffer.text_for_range(offset..selected_range.start));
+        write_selection_marker(
+            &mut summary,
+            buffer,
+            next_selection_ix,
+            selected_range.clone(),
+        );
         offset = selected_range.end;
+        next_selection_ix += 1;
     }
 
     summary.extend(buffer.text_for_range(offset..buffer.len()));
     summary
 }
 
+/// Walks the buffer's embedding-query matches to find the smallest `@item` node (function,
+/// struct, impl, trait, ...) that fully contains `range`, if any.
+fn find_enclosing_item_node(buffer: &BufferSnapshot, range: Range<usize>) -> Option<Range<usize>> {
+    item_ranges_for(buffer, range.clone())
+        .into_iter()
+        .filter(|item_range| item_range.start <= range.start && item_range.end >= range.end)
+        .min_by_key(|item_range| item_range.len())
+}
+
+/// `ranges` must be sorted in buffer order and non-overlapping; each one becomes its own
+/// numbered `<|START:n|...|END:n|>` marker pair (1-based) in the emitted prompt.
 pub fn generate_content_prompt(
     user_prompt: String,
     language_name: Option<&str>,
     buffer: &BufferSnapshot,
-    range: Range<impl ToOffset>,
+    ranges: Vec<Range<impl ToOffset>>,
     kind: CodegenKind,
     search_results: Vec<PromptCodeSnippet>,
     model: &str,
+    expand_selection_to_item: bool,
 ) -> String {
-    const MAXIMUM_SNIPPET_TOKEN_COUNT: usize = 500;
     const RESERVED_TOKENS_FOR_GENERATION: usize = 1000;
+    // Once the verbatim file content would cost more than this many tokens, fall back to
+    // a collapsed outline (signatures only, selections kept verbatim) instead of embedding
+    // the whole file.
+    const MAXIMUM_FILE_TOKEN_COUNT: usize = 1500;
 
     let mut prompts = Vec::new();
-    let range = range.to_offset(buffer);
+    let ranges = ranges
+        .into_iter()
+        .map(|range| range.to_offset(buffer))
+        .collect::<Vec<_>>();
+    debug_assert!(
+        ranges.windows(2).all(|pair| pair[0].end <= pair[1].start),
+        "ranges must be sorted in buffer order and non-overlapping"
+    );
 
     // General Preamble
     if let Some(language_name) = language_name {
@@ -193,17 +382,22 @@ pub fn generate_content_prompt(
     let mut snippet_position = prompts.len() - 1;
 
     let mut content = String::new();
-    content.extend(buffer.text_for_range(0..range.start));
-    if range.start == range.end {
-        content.push_str("<|START|>");
-    } else {
-        content.push_str("<|START|");
+    let mut offset = 0;
+    for (ix, range) in ranges.iter().enumerate() {
+        content.extend(buffer.text_for_range(offset..range.start));
+        write_selection_marker(&mut content, buffer, ix, range.clone());
+        offset = range.end;
     }
-    content.extend(buffer.text_for_range(range.clone()));
-    if range.start != range.end {
-        content.push_str("|END|>");
+    content.extend(buffer.text_for_range(offset..buffer.len()));
+
+    if let Ok(encoding) = tiktoken_rs::get_bpe_from_model(model) {
+        let file_token_count = encoding.encode_with_special_tokens(content.as_str()).len();
+        if file_token_count > MAXIMUM_FILE_TOKEN_COUNT {
+            // The whole file is too large to embed verbatim, so collapse every
+            // function/impl/block body to its signature, keeping the selections intact.
+            content = summarize(buffer, &ranges);
+        }
     }
-    content.extend(buffer.text_for_range(range.end..buffer.len()));
 
     prompts.push("The file you are currently working on has the following content:\n".to_string());
 
@@ -216,23 +410,58 @@ pub fn generate_content_prompt(
 
     match kind {
         CodegenKind::Generate { position: _ } => {
-            prompts.push("In particular, the user's cursor is currently on the '<|START|>' span in the above outline, with no text selected.".to_string());
-            prompts
-                .push("Assume the cursor is located where the `<|START|` marker is.".to_string());
-            prompts.push(
-                "Text can't be replaced, so assume your answer will be inserted at the cursor."
-                    .to_string(),
-            );
+            if ranges.len() == 1 {
+                prompts.push("In particular, the user's cursor is currently on the '<|START:1|>' span in the above outline, with no text selected.".to_string());
+                prompts.push(
+                    "Assume the cursor is located where the `<|START:1|` marker is.".to_string(),
+                );
+                prompts.push(
+                    "Text can't be replaced, so assume your answer will be inserted at the cursor."
+                        .to_string(),
+                );
+            } else {
+                prompts.push(format!("In particular, the user's cursor is currently on {} separate '<|START:n|>' spans in the above outline, with no text selected at any of them.", ranges.len()));
+                prompts.push(
+                    "Assume the cursor is located where each numbered `<|START:n|` marker is."
+                        .to_string(),
+                );
+                prompts.push("Text can't be replaced, so assume your answer for each marker will be inserted at that marker's cursor, one generated section per marker, in order.".to_string());
+            }
             prompts.push(format!(
                 "Generate text based on the users prompt: {user_prompt}"
             ));
         }
         CodegenKind::Transform { range: _ } => {
-            prompts.push("In particular, the user has selected a section of the text between the '<|START|' and '|END|>' spans.".to_string());
-            prompts.push(format!(
-                "Modify the users code selected text based upon the users prompt: '{user_prompt}'"
-            ));
-            prompts.push("You MUST reply with only the adjusted code (within the '<|START|' and '|END|>' spans), not the entire file.".to_string());
+            if ranges.len() == 1 {
+                prompts.push("In particular, the user has selected a section of the text between the '<|START:1|' and '|END:1|>' spans.".to_string());
+            } else {
+                prompts.push(format!("In particular, the user has selected {} sections of the text, each marked with its own numbered '<|START:n|' and '|END:n|>' spans.", ranges.len()));
+            }
+            if expand_selection_to_item {
+                for (ix, range) in ranges.iter().enumerate() {
+                    if let Some(item_range) = find_enclosing_item_node(buffer, range.clone()) {
+                        if &item_range != range {
+                            let enclosing_text =
+                                buffer.text_for_range(item_range).collect::<String>();
+                            let marker_ix = ix + 1;
+                            prompts.push(format!(
+                                "For additional context, here is the complete item that encloses selection {marker_ix}:\n```\n{enclosing_text}\n```"
+                            ));
+                        }
+                    }
+                }
+            }
+            if ranges.len() == 1 {
+                prompts.push(format!(
+                    "Modify the users code selected text based upon the users prompt: '{user_prompt}'"
+                ));
+                prompts.push("You MUST reply with only the adjusted code (within the '<|START:1|' and '|END:1|>' spans), not the entire file.".to_string());
+            } else {
+                prompts.push(format!(
+                    "Modify each of the users selected sections of code based upon the users prompt: '{user_prompt}'"
+                ));
+                prompts.push("You MUST reply with only the adjusted code for each numbered selection, in order, one replacement per '<|START:n|' and '|END:n|>' span, not the entire file.".to_string());
+            }
         }
     }
 
@@ -252,7 +481,7 @@ pub fn generate_content_prompt(
         name: None,
     }];
 
-    let mut remaining_token_count = if let Ok(current_token_count) =
+    let remaining_token_count = if let Ok(current_token_count) =
         tiktoken_rs::num_tokens_from_messages(model, &current_messages)
     {
         let max_token_count = tiktoken_rs::model::get_context_size(model);
@@ -273,31 +502,114 @@ pub fn generate_content_prompt(
     //   - add file path
     //   - add language
     if let Ok(encoding) = tiktoken_rs::get_bpe_from_model(model) {
-        let mut template = "You are working inside a large repository, here are a few code snippets that may be useful";
+        let weighted_snippets = search_results
+            .iter()
+            .filter_map(|search_result| {
+                let text = format!("```\n{}\n```", search_result.to_string());
+                let token_count = encoding.encode_with_special_tokens(text.as_str()).len();
+                if token_count < MAXIMUM_SNIPPET_TOKEN_COUNT {
+                    Some(WeightedSnippet {
+                        text,
+                        token_count,
+                        score: search_result.score,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut selected = pack_snippets_by_relevance(&weighted_snippets, remaining_token_count)
+            .into_iter()
+            .map(|ix| &weighted_snippets[ix])
+            .collect::<Vec<_>>();
+        // Insert the snippets in descending order of relevance, so the most relevant one comes first.
+        selected.sort_unstable_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(cmp::Ordering::Equal)
+        });
 
-        for search_result in search_results {
+        let mut template = "You are working inside a large repository, here are a few code snippets that may be useful";
+        for snippet in selected {
             let mut snippet_prompt = template.to_string();
-            let snippet = search_result.to_string();
-            writeln!(snippet_prompt, "```\n{snippet}\n```").unwrap();
+            writeln!(snippet_prompt, "{}", snippet.text).unwrap();
+            prompts.insert(snippet_position, snippet_prompt);
+            snippet_position += 1;
+            // If you have already added the template to the prompt, remove the template.
+            template = "";
+        }
+    }
 
-            let token_count = encoding
-                .encode_with_special_tokens(snippet_prompt.as_str())
-                .len();
-            if token_count <= remaining_token_count {
-                if token_count < MAXIMUM_SNIPPET_TOKEN_COUNT {
-                    prompts.insert(snippet_position, snippet_prompt);
-                    snippet_position += 1;
-                    remaining_token_count -= token_count;
-                    // If you have already added the template to the prompt, remove the template.
-                    template = "";
+    prompts.join("\n")
+}
+
+struct WeightedSnippet {
+    text: String,
+    token_count: usize,
+    score: f32,
+}
+
+/// Solves a bounded 0/1 knapsack over coarse token buckets to pick the subset of `items` that
+/// maximizes total relevance `score` without exceeding `budget_tokens`, rather than greedily
+/// taking snippets in arrival order and stopping at the first one that doesn't fit. Falls back
+/// to a value/weight-ratio greedy pass when the budget is too large for the DP table to stay
+/// small.
+fn pack_snippets_by_relevance(items: &[WeightedSnippet], budget_tokens: usize) -> Vec<usize> {
+    const BUCKET_TOKENS: usize = 50;
+    const MAXIMUM_DP_BUCKETS: usize = 4000;
+
+    let capacity = budget_tokens / BUCKET_TOKENS;
+    let weights = items
+        .iter()
+        .map(|item| (item.token_count + BUCKET_TOKENS - 1) / BUCKET_TOKENS)
+        .collect::<Vec<_>>();
+
+    if capacity > MAXIMUM_DP_BUCKETS {
+        let mut order = (0..items.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| {
+            let ratio_a = items[a].score / items[a].token_count.max(1) as f32;
+            let ratio_b = items[b].score / items[b].token_count.max(1) as f32;
+            ratio_b
+                .partial_cmp(&ratio_a)
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+        let mut remaining = budget_tokens;
+        let mut selected = Vec::new();
+        for ix in order {
+            if items[ix].token_count <= remaining {
+                remaining -= items[ix].token_count;
+                selected.push(ix);
+            }
+        }
+        return selected;
+    }
+
+    let item_count = items.len();
+    let mut dp = vec![vec![0f32; capacity + 1]; item_count + 1];
+    for i in 1..=item_count {
+        let weight = weights[i - 1];
+        let value = items[i - 1].score;
+        for w in 0..=capacity {
+            dp[i][w] = dp[i - 1][w];
+            if weight <= w {
+                let candidate = dp[i - 1][w - weight] + value;
+                if candidate > dp[i][w] {
+                    dp[i][w] = candidate;
                 }
-            } else {
-                break;
             }
         }
     }
 
-    prompts.join("\n")
+    let mut selected = Vec::new();
+    let mut w = capacity;
+    for i in (1..=item_count).rev() {
+        if dp[i][w] != dp[i - 1][w] {
+            selected.push(i - 1);
+            w = w.saturating_sub(weights[i - 1]);
+        }
+    }
+    selected
 }
 
 #[cfg(test)]
@@ -387,10 +699,13 @@ pub(crate) mod tests {
         let snapshot = buffer.read(cx).snapshot();
 
         assert_eq!(
-            summarize(&snapshot, Point::new(1, 4)..Point::new(1, 4)),
+            summarize(
+                &snapshot,
+                &[(Point::new(1, 4)..Point::new(1, 4)).to_offset(&snapshot)]
+            ),
             indoc! {"
                 struct X {
-                    <|START|>a: usize,
+                    <|START:1|>a: usize,
                     b: usize,
                 }
 
@@ -406,7 +721,10 @@ pub(crate) mod tests {
         );
 
         assert_eq!(
-            summarize(&snapshot, Point::new(8, 12)..Point::new(8, 14)),
+            summarize(
+                &snapshot,
+                &[(Point::new(8, 12)..Point::new(8, 14)).to_offset(&snapshot)]
+            ),
             indoc! {"
                 struct X {
                     a: usize,
@@ -416,7 +734,7 @@ pub(crate) mod tests {
                 impl X {
 
                     fn new() -> Self {
-                        let <|START|a |END|>= 1;
+                        let <|START:1|a |END:1|>= 1;
                         let b = 2;
                         Self { a, b }
                     }
@@ -429,7 +747,10 @@ pub(crate) mod tests {
         );
 
         assert_eq!(
-            summarize(&snapshot, Point::new(6, 0)..Point::new(6, 0)),
+            summarize(
+                &snapshot,
+                &[(Point::new(6, 0)..Point::new(6, 0)).to_offset(&snapshot)]
+            ),
             indoc! {"
                 struct X {
                     a: usize,
@@ -437,7 +758,7 @@ pub(crate) mod tests {
                 }
 
                 impl X {
-                <|START|>
+                <|START:1|>
                     fn new() -> Self {}
 
                     pub fn a(&self, param: bool) -> usize {}
@@ -448,7 +769,10 @@ pub(crate) mod tests {
         );
 
         assert_eq!(
-            summarize(&snapshot, Point::new(21, 0)..Point::new(21, 0)),
+            summarize(
+                &snapshot,
+                &[(Point::new(21, 0)..Point::new(21, 0)).to_offset(&snapshot)]
+            ),
             indoc! {"
                 struct X {
                     a: usize,
@@ -463,7 +787,37 @@ pub(crate) mod tests {
 
                     pub fn b(&self) -> usize {}
                 }
-                <|START|>"}
+                <|START:1|>"}
+        );
+
+        // Multiple, disjoint selections each get their own numbered marker pair.
+        assert_eq!(
+            summarize(
+                &snapshot,
+                &[
+                    (Point::new(1, 4)..Point::new(1, 4)).to_offset(&snapshot),
+                    (Point::new(8, 12)..Point::new(8, 14)).to_offset(&snapshot),
+                ]
+            ),
+            indoc! {"
+                struct X {
+                    <|START:1|>a: usize,
+                    b: usize,
+                }
+
+                impl X {
+
+                    fn new() -> Self {
+                        let <|START:2|a |END:2|>= 1;
+                        let b = 2;
+                        Self { a, b }
+                    }
+
+                    pub fn a(&self, param: bool) -> usize {}
+
+                    pub fn b(&self) -> usize {}
+                }
+            "}
         );
 
         // Ensure nested functions get collapsed properly.
@@ -497,9 +851,12 @@ pub(crate) mod tests {
         buffer.update(cx, |buffer, cx| buffer.set_text(text, cx));
         let snapshot = buffer.read(cx).snapshot();
         assert_eq!(
-            summarize(&snapshot, Point::new(0, 0)..Point::new(0, 0)),
+            summarize(
+                &snapshot,
+                &[(Point::new(0, 0)..Point::new(0, 0)).to_offset(&snapshot)]
+            ),
             indoc! {"
-                <|START|>struct X {
+                <|START:1|>struct X {
                     a: usize,
                     b: usize,
                 }
@@ -515,4 +872,260 @@ pub(crate) mod tests {
             "}
         );
     }
-}
\ No newline at end of file
+
+    #[gpui::test]
+    fn test_syntax_aware_trim_under_budget(cx: &mut AppContext) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+        let text = indoc! {"
+            struct X {
+                a: usize,
+            }
+
+            impl X {
+                fn new() -> Self {
+                    Self { a: 1 }
+                }
+            }
+        "};
+        let buffer =
+            cx.add_model(|cx| Buffer::new(0, 0, text).with_language(Arc::new(rust_lang()), cx));
+        let snapshot = buffer.read(cx).snapshot();
+
+        assert_eq!(
+            PromptCodeSnippet::syntax_aware_trim(&snapshot, 0..text.len(), "gpt-4"),
+            text
+        );
+    }
+
+    #[gpui::test]
+    fn test_syntax_aware_trim_keeps_sibling_declarations_without_duplicating_ancestor(
+        cx: &mut AppContext,
+    ) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+
+        // Many small sibling methods whose combined text exceeds the snippet budget, even
+        // though each individual method is tiny.
+        let methods = (0..150)
+            .map(|ix| format!("    fn method_{ix}() -> usize {{\n        {ix}\n    }}\n"))
+            .collect::<String>();
+        let text = format!("impl X {{\n{methods}}}\n");
+        let buffer = cx.add_model(|cx| {
+            Buffer::new(0, 0, text.clone()).with_language(Arc::new(rust_lang()), cx)
+        });
+        let snapshot = buffer.read(cx).snapshot();
+
+        let trimmed = PromptCodeSnippet::syntax_aware_trim(&snapshot, 0..text.len(), "gpt-4");
+
+        // Every method survives, in full, exactly once...
+        for ix in 0..150 {
+            let needle = format!("fn method_{ix}() -> usize {{\n        {ix}\n    }}");
+            assert_eq!(
+                trimmed.matches(&needle).count(),
+                1,
+                "method_{ix} should appear exactly once"
+            );
+        }
+        // ...and the enclosing `impl` isn't also emitted as a redundant wrapper around them:
+        // `impl_item` and each nested `function_item` all overlap the search range, but only
+        // the innermost (the methods) should be kept.
+        assert_eq!(trimmed.matches("impl X").count(), 0);
+    }
+
+    #[gpui::test]
+    fn test_syntax_aware_trim_collapses_oversized_declaration(cx: &mut AppContext) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+
+        // A single method whose body alone blows the snippet budget: even after narrowing to
+        // the enclosing declaration, it doesn't fit, so its body must be elided.
+        let big_body = "        let x = 1;\n".repeat(400);
+        let text =
+            format!("impl X {{\n    fn big() -> usize {{\n{big_body}        2\n    }}\n}}\n");
+        let buffer = cx.add_model(|cx| {
+            Buffer::new(0, 0, text.clone()).with_language(Arc::new(rust_lang()), cx)
+        });
+        let snapshot = buffer.read(cx).snapshot();
+
+        let trimmed = PromptCodeSnippet::syntax_aware_trim(&snapshot, 0..text.len(), "gpt-4");
+
+        assert!(trimmed.contains("fn big() -> usize {}"));
+        assert!(!trimmed.contains("let x = 1;"));
+    }
+
+    #[gpui::test]
+    fn test_find_enclosing_item_node_picks_smallest_nested_item(cx: &mut AppContext) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+        let text = indoc! {"
+            impl X {
+                fn a(&self) -> usize {
+                    1
+                }
+            }
+        "};
+        let buffer =
+            cx.add_model(|cx| Buffer::new(0, 0, text).with_language(Arc::new(rust_lang()), cx));
+        let snapshot = buffer.read(cx).snapshot();
+
+        let body_offset = text.find('1').unwrap();
+        let enclosing = find_enclosing_item_node(&snapshot, body_offset..body_offset + 1)
+            .expect("the `1` literal is enclosed by the `fn a` method");
+        let enclosing_text = snapshot.text_for_range(enclosing).collect::<String>();
+
+        // The method, not the enclosing `impl`, is the smallest node containing the selection.
+        assert!(enclosing_text.starts_with("fn a(&self) -> usize {"));
+        assert!(!enclosing_text.starts_with("impl X"));
+    }
+
+    #[gpui::test]
+    fn test_find_enclosing_item_node_matches_exact_item_bounds(cx: &mut AppContext) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+        let text = indoc! {"
+            impl X {
+                fn a(&self) -> usize {
+                    1
+                }
+            }
+        "};
+        let buffer =
+            cx.add_model(|cx| Buffer::new(0, 0, text).with_language(Arc::new(rust_lang()), cx));
+        let snapshot = buffer.read(cx).snapshot();
+
+        let body_offset = text.find('1').unwrap();
+        let method_range = find_enclosing_item_node(&snapshot, body_offset..body_offset + 1)
+            .expect("the `1` literal is enclosed by the `fn a` method");
+
+        // A selection spanning exactly the method's own bounds should resolve to that same
+        // method, not expand out to the enclosing `impl`.
+        assert_eq!(
+            find_enclosing_item_node(&snapshot, method_range.clone()),
+            Some(method_range)
+        );
+    }
+
+    #[gpui::test]
+    fn test_find_enclosing_item_node_returns_none_for_top_level_selection(cx: &mut AppContext) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+        let text = indoc! {"
+            fn a() -> usize {
+                1
+            }
+
+            fn b() -> usize {
+                2
+            }
+        "};
+        let buffer =
+            cx.add_model(|cx| Buffer::new(0, 0, text).with_language(Arc::new(rust_lang()), cx));
+        let snapshot = buffer.read(cx).snapshot();
+
+        // The blank line between the two top-level functions isn't inside either item.
+        let gap_offset = text.find("\n\n").unwrap() + 1;
+        assert_eq!(
+            find_enclosing_item_node(&snapshot, gap_offset..gap_offset),
+            None
+        );
+    }
+
+    #[gpui::test]
+    fn test_generate_content_prompt_expands_transform_selection_to_enclosing_item(
+        cx: &mut AppContext,
+    ) {
+        cx.set_global(SettingsStore::test(cx));
+        language_settings::init(cx);
+        let text = indoc! {"
+            impl X {
+                fn a(&self) -> usize {
+                    1
+                }
+            }
+        "};
+        let buffer =
+            cx.add_model(|cx| Buffer::new(0, 0, text).with_language(Arc::new(rust_lang()), cx));
+        let snapshot = buffer.read(cx).snapshot();
+
+        let selection_start = text.find('1').unwrap();
+        let selection_end = selection_start + 1;
+
+        let prompt = generate_content_prompt(
+            "make this louder".to_string(),
+            Some("rust"),
+            &snapshot,
+            vec![selection_start..selection_end],
+            CodegenKind::Transform {
+                range: snapshot.anchor_before(selection_start)
+                    ..snapshot.anchor_after(selection_end),
+            },
+            vec![],
+            "gpt-4",
+            true,
+        );
+
+        assert!(prompt.contains(
+            "For additional context, here is the complete item that encloses selection 1:"
+        ));
+        assert!(prompt.contains("fn a(&self) -> usize {"));
+    }
+
+    #[test]
+    fn test_pack_snippets_by_relevance_prefers_total_relevance() {
+        let items = vec![
+            WeightedSnippet {
+                text: "low score, large".into(),
+                token_count: 400,
+                score: 0.1,
+            },
+            WeightedSnippet {
+                text: "high score, small #1".into(),
+                token_count: 150,
+                score: 0.9,
+            },
+            WeightedSnippet {
+                text: "high score, small #2".into(),
+                token_count: 150,
+                score: 0.9,
+            },
+        ];
+
+        // Greedy first-fit (arrival order) would stop at the large, low-score item, which doesn't
+        // fit, and take neither of the two small, high-relevance ones. The knapsack takes both.
+        let mut selected = pack_snippets_by_relevance(&items, 300);
+        selected.sort_unstable();
+        assert_eq!(selected, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_pack_snippets_by_relevance_zero_budget() {
+        let items = vec![WeightedSnippet {
+            text: "a".into(),
+            token_count: 10,
+            score: 1.0,
+        }];
+        assert!(pack_snippets_by_relevance(&items, 0).is_empty());
+    }
+
+    #[test]
+    fn test_pack_snippets_by_relevance_greedy_fallback_for_large_budgets() {
+        // A budget whose bucket count exceeds `MAXIMUM_DP_BUCKETS` takes the ratio-greedy path
+        // instead of building the DP table, and should still prioritize by score-per-token.
+        let items = vec![
+            WeightedSnippet {
+                text: "poor ratio".into(),
+                token_count: 250_000,
+                score: 0.1,
+            },
+            WeightedSnippet {
+                text: "great ratio".into(),
+                token_count: 1_000,
+                score: 0.9,
+            },
+        ];
+        let huge_budget = 201_000; // 201_000 / 50 = 4_020 buckets > MAXIMUM_DP_BUCKETS (4_000)
+        let selected = pack_snippets_by_relevance(&items, huge_budget);
+        assert_eq!(selected, vec![1]);
+    }
+}